@@ -0,0 +1,323 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use async_compression::{tokio::write::ZstdEncoder, Level};
+use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::{
+    io,
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+use tokio_util::{io::SinkWriter, io::StreamReader, sync::PollSender};
+use tracing::{info, warn};
+
+use crate::{hashing::HashingReader, ratelimit::RateLimiter, Job};
+
+/// One archive entry in flight: the writer task copies compressed chunks off
+/// `body` into the entry as they arrive. The fetch side buffers each
+/// attempt's compressed output in memory until that attempt has succeeded,
+/// so a symbol's whole compressed body is held in memory before it is
+/// forwarded; the uncompressed body is never held whole. Entries are written
+/// in the order their `ArchiveJob` was handed to the writer, i.e. manifest
+/// order, not completion order.
+/// `done` reports whether the fetch ultimately succeeded; the writer checks
+/// it before touching `body`, since a failed fetch closes `body` with zero
+/// chunks too — the same shape as a legitimately empty one.
+pub(crate) struct ArchiveJob {
+    symbol: String,
+    body: mpsc::Receiver<Bytes>,
+    done: oneshot::Receiver<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    symbol: String,
+    entry: String,
+    compressed_bytes: u64,
+}
+
+/// Fetches and zstd-compresses every job, funneling the compressed bytes of
+/// each into one streaming ZIP archive instead of one `.zst` file per symbol.
+/// Returns (ok, failed) counts in the same shape as the per-file `Batch` path.
+pub(crate) async fn run_batch_archive(
+    client: &Client,
+    jobs: Vec<Job>,
+    archive_path: PathBuf,
+    level: i32,
+    concurrency: usize,
+    retries: u32,
+    limiter: Arc<RateLimiter>,
+) -> Result<(usize, usize)> {
+    let (jobs_tx, jobs_rx) = mpsc::channel::<ArchiveJob>(concurrency.max(1));
+    let writer = tokio::spawn(write_archive(archive_path, jobs_rx));
+
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut fetches = futures::stream::FuturesUnordered::new();
+
+    for job in jobs {
+        let (body_tx, body_rx) = mpsc::channel::<Bytes>(8);
+        let (done_tx, done_rx) = oneshot::channel::<bool>();
+        jobs_tx
+            .send(ArchiveJob {
+                symbol: job.symbol.clone(),
+                body: body_rx,
+                done: done_rx,
+            })
+            .await
+            .context("archive writer task ended early")?;
+
+        let client = client.clone();
+        let semaphore = semaphore.clone();
+        let limiter = limiter.clone();
+        fetches.push(tokio::spawn(async move {
+            let symbol = job.symbol.clone();
+            let fetched =
+                fetch_with_retries(&client, &job, level, retries, &limiter, &semaphore).await;
+            // Report the outcome before forwarding: the writer awaits `done`
+            // before it drains `body`, so forwarding first would stall on the
+            // bounded channel once more than its capacity is queued.
+            let _ = done_tx.send(fetched.is_ok());
+            let result = match fetched {
+                Ok(chunks) => {
+                    for chunk in chunks {
+                        if body_tx.send(chunk).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            };
+            (symbol, result)
+        }));
+    }
+    drop(jobs_tx);
+
+    let mut ok = 0usize;
+    let mut failed = 0usize;
+    while let Some(joined) = fetches.next().await {
+        let (symbol, result) = joined.context("archive fetch task panicked")?;
+        match result {
+            Ok(()) => {
+                info!("✔ {symbol} -> archive entry");
+                ok += 1;
+            }
+            Err(err) => {
+                warn!("job failed: {err:?}");
+                failed += 1;
+            }
+        }
+    }
+
+    let manifest = writer
+        .await
+        .context("archive writer task panicked")?
+        .context("writing archive")?;
+    info!("archive done: {} entries", manifest.len());
+
+    Ok((ok, failed))
+}
+
+async fn fetch_with_retries(
+    client: &Client,
+    job: &Job,
+    level: i32,
+    retries: u32,
+    limiter: &RateLimiter,
+    semaphore: &tokio::sync::Semaphore,
+) -> Result<Vec<Bytes>> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        // Hold the permit only for the network+compress attempt itself. The
+        // writer drains entries in manifest order, so once this attempt has
+        // succeeded, the caller forwarding its chunks to `body` (capacity 8) can
+        // block for as long as the writer is still busy with an earlier
+        // symbol; holding the permit across that forward would let enough
+        // later-symbol tasks pile up blocked-on-send to exhaust every permit
+        // before the writer reaches the symbol it's actually waiting on,
+        // deadlocking the whole batch.
+        let permit = semaphore.acquire().await.expect("semaphore closed");
+
+        // Each attempt gets a fresh channel: a partially-sent prior attempt
+        // must not leave stale bytes in the entry the writer is consuming.
+        // The channel is bounded, so its chunks are drained concurrently by
+        // `collect_chunks` as `fetch_compressed` produces them; only once the
+        // whole attempt has succeeded are they returned for forwarding.
+        let (tx, rx) = mpsc::channel::<Bytes>(8);
+        let collector = tokio::spawn(collect_chunks(rx));
+        let fetch = fetch_compressed(client, job, level, tx, limiter);
+        match fetch.await {
+            Ok(()) => {
+                let chunks = collector.await.context("archive chunk collector panicked")?;
+                drop(permit);
+                return Ok(chunks);
+            }
+            Err(err) if attempts <= retries => {
+                drop(permit);
+                collector.abort();
+                let wait = crate::ratelimit::backoff_for(&err, attempts);
+                warn!(
+                    "retry {}/{} for {} in {:?} due to {err:?}",
+                    attempts, retries, job.symbol, wait
+                );
+                tokio::time::sleep(wait).await;
+            }
+            Err(err) => {
+                drop(permit);
+                collector.abort();
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Drains a channel as its producer streams chunks in, so the producer's
+/// `send` never blocks on backpressure from an attempt that hasn't finished
+/// (or failed) yet. Returns the collected chunks once the sender is dropped.
+async fn collect_chunks(mut rx: mpsc::Receiver<Bytes>) -> Vec<Bytes> {
+    let mut chunks = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+async fn fetch_compressed(
+    client: &Client,
+    job: &Job,
+    level: i32,
+    chunk_tx: mpsc::Sender<Bytes>,
+    limiter: &RateLimiter,
+) -> Result<()> {
+    let mut req = client.get(&job.url).header(
+        reqwest::header::USER_AGENT,
+        "stockdatadump/0.1 (https://github.com/your/repo)",
+    );
+    if let Some(headers) = &job.headers {
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+    }
+    limiter.acquire_request(&job.url).await;
+    let resp = req
+        .send()
+        .await
+        .with_context(|| format!("requesting {}", job.url))?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+    {
+        let retry_after = crate::ratelimit::parse_retry_after(resp.headers());
+        return Err(crate::ratelimit::RateLimited { status, retry_after }.into())
+            .with_context(|| format!("rate limited requesting {}", job.url));
+    }
+    if !status.is_success() {
+        anyhow::bail!("non-2xx {} for {}", status, job.url);
+    }
+
+    let byte_stream = resp.bytes_stream().map(|res| {
+        res.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("http stream error: {e}")))
+    });
+    let mut reader =
+        HashingReader::new(limiter.wrap_reader(&job.url, StreamReader::new(byte_stream)));
+
+    let sink = PollSender::new(chunk_tx)
+        .sink_map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()));
+    let writer = SinkWriter::new(sink);
+    let mut encoder = ZstdEncoder::with_quality(writer, Level::Precise(level));
+
+    io::copy(&mut reader, &mut encoder)
+        .await
+        .with_context(|| format!("compressing {}", job.url))?;
+    encoder
+        .shutdown()
+        .await
+        .context("finalizing compressed stream")?;
+
+    if let Some(expected) = &job.expected_digest {
+        let digest = reader.finalize_hex();
+        if !expected.eq_ignore_ascii_case(&digest) {
+            anyhow::bail!(
+                "blake3 mismatch for {}: expected {expected}, got {digest}",
+                job.symbol
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_archive(
+    path: PathBuf,
+    mut jobs_rx: mpsc::Receiver<ArchiveJob>,
+) -> Result<Vec<ManifestEntry>> {
+    let file = tokio::fs::File::create(&path)
+        .await
+        .with_context(|| format!("creating archive {path:?}"))?;
+    let mut zip = ZipFileWriter::with_tokio(file);
+    let mut manifest = Vec::new();
+
+    while let Some(mut archive_job) = jobs_rx.recv().await {
+        // A failed fetch closes `body` with zero chunks sent, same as a
+        // legitimately empty one — `done` is the only way to tell them
+        // apart, so check it before opening an entry for this symbol.
+        let succeeded = archive_job.done.await.unwrap_or(false);
+        if !succeeded {
+            warn!(
+                "skipping archive entry for {}: fetch failed",
+                archive_job.symbol
+            );
+            continue;
+        }
+
+        let entry_name = format!("{}.zst", archive_job.symbol);
+        let builder = ZipEntryBuilder::new(entry_name.clone().into(), Compression::Stored);
+        let mut entry_writer = zip
+            .write_entry_stream(builder)
+            .await
+            .with_context(|| format!("opening archive entry {entry_name}"))?;
+
+        let mut compressed_bytes = 0u64;
+        while let Some(chunk) = archive_job.body.recv().await {
+            entry_writer
+                .write_all(&chunk)
+                .await
+                .with_context(|| format!("writing archive entry {entry_name}"))?;
+            compressed_bytes += chunk.len() as u64;
+        }
+        entry_writer
+            .close()
+            .await
+            .with_context(|| format!("closing archive entry {entry_name}"))?;
+
+        manifest.push(ManifestEntry {
+            symbol: archive_job.symbol,
+            entry: entry_name,
+            compressed_bytes,
+        });
+    }
+
+    let manifest_json =
+        serde_json::to_vec_pretty(&manifest).context("serializing archive manifest")?;
+    let builder = ZipEntryBuilder::new("manifest.json".into(), Compression::Deflate);
+    let mut manifest_writer = zip
+        .write_entry_stream(builder)
+        .await
+        .context("opening manifest.json entry")?;
+    manifest_writer
+        .write_all(&manifest_json)
+        .await
+        .context("writing manifest.json entry")?;
+    manifest_writer
+        .close()
+        .await
+        .context("closing manifest.json entry")?;
+
+    zip.close().await.context("finalizing archive")?;
+    Ok(manifest)
+}
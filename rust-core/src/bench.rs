@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{fetch_and_write, ratelimit::RateLimiter, Job};
+
+/// A replayable workload: a fixed set of jobs, run `runs` times at up to
+/// `concurrency` in flight, modeled on the existing `Batch` pipeline.
+#[derive(Debug, Deserialize)]
+struct Workload {
+    name: String,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default = "default_runs")]
+    runs: usize,
+    jobs: Vec<Job>,
+}
+
+fn default_concurrency() -> usize {
+    8
+}
+
+fn default_runs() -> usize {
+    1
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct JobSample {
+    symbol: String,
+    ok: bool,
+    latency_ms: f64,
+    bytes_downloaded: u64,
+    bytes_written: u64,
+    compression_ratio: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RunReport {
+    run: usize,
+    wall_ms: f64,
+    aggregate_mb_s: f64,
+    samples: Vec<JobSample>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LatencyStats {
+    min_ms: f64,
+    median_ms: f64,
+    p95_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct EnvInfo {
+    hostname: String,
+    cpu_count: usize,
+    crate_version: &'static str,
+    zstd_level: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BenchReport {
+    workload: String,
+    env: EnvInfo,
+    latency: LatencyStats,
+    runs: Vec<RunReport>,
+}
+
+/// Loads a workload file and replays its jobs through `fetch_and_write`,
+/// `runs` times, recording per-job latency/throughput plus aggregate stats.
+pub(crate) async fn run_workload(
+    client: &Client,
+    path: &Path,
+    level: i32,
+    retries: u32,
+    limiter: &RateLimiter,
+) -> Result<BenchReport> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading workload {path:?}"))?;
+    let workload: Workload =
+        serde_json::from_str(&text).with_context(|| format!("parsing workload {path:?}"))?;
+
+    let scratch = std::env::temp_dir().join(format!("dump-core-bench-{}", workload.name));
+    tokio::fs::create_dir_all(&scratch)
+        .await
+        .with_context(|| format!("creating scratch dir {scratch:?}"))?;
+
+    let mut runs = Vec::with_capacity(workload.runs);
+    let mut all_latencies_ms = Vec::new();
+
+    for run in 0..workload.runs {
+        // Each run gets its own scratch subdir: reusing one across runs would
+        // let run 2+ hit the `.etag` sidecar `fetch_once` writes on run 1 and
+        // get back 304s, measuring cache hits instead of the real workload.
+        let run_scratch = scratch.join(format!("run-{run}"));
+        tokio::fs::create_dir_all(&run_scratch)
+            .await
+            .with_context(|| format!("creating scratch dir {run_scratch:?}"))?;
+
+        let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(workload.concurrency));
+        let wall_start = std::time::Instant::now();
+
+        let mut stream = futures::stream::iter(workload.jobs.iter().cloned().map(|job| {
+            let client = client.clone();
+            let semaphore = semaphore.clone();
+            let output = run_scratch.join(format!("{}.zst", job.symbol));
+            async move {
+                let _permit = semaphore.acquire().await.expect("semaphore closed");
+                let started = std::time::Instant::now();
+                let result =
+                    fetch_and_write(&client, job.clone(), output, level, retries, limiter).await;
+                (job.symbol, started.elapsed(), result)
+            }
+        }))
+        .buffer_unordered(workload.concurrency);
+
+        let mut samples = Vec::with_capacity(workload.jobs.len());
+        let mut run_bytes = 0u64;
+        while let Some((symbol, elapsed, result)) = stream.next().await {
+            let latency_ms = elapsed.as_secs_f64() * 1000.0;
+            let sample = match result {
+                Ok(stats) => {
+                    run_bytes += stats.bytes_downloaded;
+                    JobSample {
+                        symbol,
+                        ok: true,
+                        latency_ms,
+                        bytes_downloaded: stats.bytes_downloaded,
+                        bytes_written: stats.bytes_written,
+                        compression_ratio: if stats.bytes_written > 0 {
+                            stats.bytes_downloaded as f64 / stats.bytes_written as f64
+                        } else {
+                            0.0
+                        },
+                    }
+                }
+                Err(err) => {
+                    warn!("bench job {symbol} failed: {err:?}");
+                    JobSample {
+                        symbol,
+                        ok: false,
+                        latency_ms,
+                        bytes_downloaded: 0,
+                        bytes_written: 0,
+                        compression_ratio: 0.0,
+                    }
+                }
+            };
+            all_latencies_ms.push(sample.latency_ms);
+            samples.push(sample);
+        }
+
+        let wall = wall_start.elapsed();
+        let aggregate_mb_s = if wall.as_secs_f64() > 0.0 {
+            (run_bytes as f64 / (1024.0 * 1024.0)) / wall.as_secs_f64()
+        } else {
+            0.0
+        };
+
+        runs.push(RunReport {
+            run,
+            wall_ms: wall.as_secs_f64() * 1000.0,
+            aggregate_mb_s,
+            samples,
+        });
+    }
+
+    let _ = tokio::fs::remove_dir_all(&scratch).await;
+
+    Ok(BenchReport {
+        workload: workload.name,
+        env: env_info(level),
+        latency: latency_stats(all_latencies_ms),
+        runs,
+    })
+}
+
+fn latency_stats(mut samples_ms: Vec<f64>) -> LatencyStats {
+    if samples_ms.is_empty() {
+        return LatencyStats {
+            min_ms: 0.0,
+            median_ms: 0.0,
+            p95_ms: 0.0,
+            max_ms: 0.0,
+        };
+    }
+    samples_ms.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| samples_ms[((samples_ms.len() - 1) as f64 * p).round() as usize];
+    LatencyStats {
+        min_ms: samples_ms[0],
+        median_ms: percentile(0.5),
+        p95_ms: percentile(0.95),
+        max_ms: *samples_ms.last().unwrap(),
+    }
+}
+
+fn env_info(zstd_level: i32) -> EnvInfo {
+    EnvInfo {
+        hostname: hostname::get()
+            .ok()
+            .and_then(|h| h.into_string().ok())
+            .unwrap_or_else(|| "unknown".to_string()),
+        cpu_count: std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        crate_version: env!("CARGO_PKG_VERSION"),
+        zstd_level,
+    }
+}
+
+/// Writes the report as pretty JSON under `dir`, tagged with the workload
+/// name and a millisecond timestamp so repeat runs don't collide.
+pub(crate) async fn write_report(dir: &Path, report: &BenchReport) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("creating report dir {dir:?}"))?;
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let path = dir.join(format!("{}-{ts}.json", report.workload));
+    let json = serde_json::to_string_pretty(report).context("serializing bench report")?;
+    tokio::fs::write(&path, json)
+        .await
+        .with_context(|| format!("writing report {path:?}"))?;
+    Ok(path)
+}
+
+pub(crate) async fn post_report(dashboard_url: &str, report: &BenchReport) -> Result<()> {
+    let client = reqwest::Client::new();
+    client
+        .post(dashboard_url)
+        .json(report)
+        .send()
+        .await
+        .with_context(|| format!("posting report to {dashboard_url}"))?
+        .error_for_status()
+        .with_context(|| format!("dashboard {dashboard_url} rejected report"))?;
+    Ok(())
+}
+
+pub(crate) fn log_summary(report: &BenchReport) {
+    for run in &report.runs {
+        info!(
+            "{} run {}: wall={:.1}ms throughput={:.2}MB/s",
+            report.workload, run.run, run.wall_ms, run.aggregate_mb_s
+        );
+    }
+    info!(
+        "{} latency: min={:.1}ms median={:.1}ms p95={:.1}ms max={:.1}ms",
+        report.workload,
+        report.latency.min_ms,
+        report.latency.median_ms,
+        report.latency.p95_ms,
+        report.latency.max_ms
+    );
+}
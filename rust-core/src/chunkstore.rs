@@ -0,0 +1,395 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use async_compression::{
+    tokio::{bufread::ZstdDecoder, write::ZstdEncoder},
+    Level,
+};
+use futures::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio_util::io::StreamReader;
+use tracing::{info, warn};
+
+use crate::{hashing::HashingReader, ratelimit::RateLimiter, Job};
+
+pub(crate) const MIN_CHUNK_SIZE: usize = 16 * 1024;
+pub(crate) const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// 20 zero bits below the hash gives an average boundary spacing of 2^20
+/// bytes (~1 MiB), matching the target average chunk size.
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+
+/// Pseudo-random 64-bit constants for the Gear hash, one per input byte
+/// value. Derived at compile time with a splitmix64 mix of the index so we
+/// don't need a `rand` dependency just to seed a lookup table.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0usize;
+    while i < 256 {
+        let mut z = (i as u64).wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        table[i] = z;
+        i += 1;
+    }
+    table
+};
+
+/// Content-defined chunker: a Gear rolling hash declares a boundary when the
+/// low `BOUNDARY_MASK` bits go to zero, clamped to `[MIN_CHUNK_SIZE,
+/// MAX_CHUNK_SIZE]` so outliers in the input can't produce degenerate
+/// chunks.
+struct Chunker {
+    hash: u64,
+    buf: Vec<u8>,
+}
+
+impl Chunker {
+    fn new() -> Self {
+        Self {
+            hash: 0,
+            buf: Vec::with_capacity(MIN_CHUNK_SIZE),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut boundaries = Vec::new();
+        for &byte in data {
+            self.buf.push(byte);
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+            let len = self.buf.len();
+            if len >= MAX_CHUNK_SIZE || (len >= MIN_CHUNK_SIZE && self.hash & BOUNDARY_MASK == 0) {
+                boundaries.push(std::mem::replace(
+                    &mut self.buf,
+                    Vec::with_capacity(MIN_CHUNK_SIZE),
+                ));
+                self.hash = 0;
+            }
+        }
+        boundaries
+    }
+
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buf))
+        }
+    }
+}
+
+/// Ordered list of chunk digests making up one symbol's body, so the
+/// original stream can be reassembled without touching the dedup store's
+/// other entries.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkIndex {
+    symbol: String,
+    total_len: u64,
+    chunks: Vec<String>,
+}
+
+fn chunk_path(store_dir: &Path, digest: &str) -> PathBuf {
+    store_dir.join(&digest[..2]).join(format!("{digest}.zst"))
+}
+
+fn index_path(store_dir: &Path, symbol: &str) -> PathBuf {
+    store_dir.join("index").join(format!("{symbol}.json"))
+}
+
+/// Fetches every job's body and splits it into content-defined, BLAKE3-keyed
+/// chunks deduplicated across the whole batch. Returns (ok, failed) counts
+/// in the same shape as the plain `Batch` path.
+pub(crate) async fn run_batch_chunked(
+    client: &Client,
+    jobs: Vec<Job>,
+    store_dir: PathBuf,
+    level: i32,
+    concurrency: usize,
+    retries: u32,
+    limiter: &RateLimiter,
+) -> Result<(usize, usize)> {
+    tokio::fs::create_dir_all(&store_dir)
+        .await
+        .with_context(|| format!("creating chunk store {store_dir:?}"))?;
+
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency));
+    let mut ok = 0usize;
+    let mut failed = 0usize;
+
+    let mut stream = futures::stream::iter(jobs.into_iter().map(|job| {
+        let client = client.clone();
+        let store_dir = store_dir.clone();
+        let semaphore = semaphore.clone();
+        async move {
+            let _permit = semaphore.acquire().await.expect("semaphore closed");
+            let symbol = job.symbol.clone();
+            let result =
+                store_with_retries(&client, &job, &store_dir, level, retries, limiter).await;
+            (symbol, result)
+        }
+    }))
+    .buffer_unordered(concurrency);
+
+    while let Some((symbol, result)) = stream.next().await {
+        match result {
+            Ok(index) => {
+                info!(
+                    "✔ {symbol} -> {} chunks, {} bytes",
+                    index.chunks.len(),
+                    index.total_len
+                );
+                ok += 1;
+            }
+            Err(err) => {
+                warn!("job failed: {err:?}");
+                failed += 1;
+            }
+        }
+    }
+
+    Ok((ok, failed))
+}
+
+async fn store_with_retries(
+    client: &Client,
+    job: &Job,
+    store_dir: &Path,
+    level: i32,
+    retries: u32,
+    limiter: &RateLimiter,
+) -> Result<ChunkIndex> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match store_job(client, job, store_dir, level, limiter).await {
+            Ok(index) => return Ok(index),
+            Err(err) if attempts <= retries => {
+                let wait = crate::ratelimit::backoff_for(&err, attempts);
+                warn!(
+                    "retry {}/{} for {} in {:?} due to {err:?}",
+                    attempts, retries, job.symbol, wait
+                );
+                tokio::time::sleep(wait).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+async fn store_job(
+    client: &Client,
+    job: &Job,
+    store_dir: &Path,
+    level: i32,
+    limiter: &RateLimiter,
+) -> Result<ChunkIndex> {
+    let mut req = client.get(&job.url).header(
+        reqwest::header::USER_AGENT,
+        "stockdatadump/0.1 (https://github.com/your/repo)",
+    );
+    if let Some(headers) = &job.headers {
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+    }
+    limiter.acquire_request(&job.url).await;
+    let resp = req
+        .send()
+        .await
+        .with_context(|| format!("requesting {}", job.url))?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+    {
+        let retry_after = crate::ratelimit::parse_retry_after(resp.headers());
+        return Err(crate::ratelimit::RateLimited { status, retry_after }.into())
+            .with_context(|| format!("rate limited requesting {}", job.url));
+    }
+    if !status.is_success() {
+        anyhow::bail!("non-2xx {} for {}", status, job.url);
+    }
+
+    let byte_stream = resp.bytes_stream().map(|res| {
+        res.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("http stream error: {e}")))
+    });
+    let mut reader =
+        HashingReader::new(limiter.wrap_reader(&job.url, StreamReader::new(byte_stream)));
+
+    let mut chunker = Chunker::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut digests = Vec::new();
+    let mut total_len = 0u64;
+
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .with_context(|| format!("reading body of {}", job.url))?;
+        if n == 0 {
+            break;
+        }
+        total_len += n as u64;
+        for chunk in chunker.push(&buf[..n]) {
+            digests.push(write_chunk_if_absent(store_dir, &chunk, level).await?);
+        }
+    }
+    if let Some(chunk) = chunker.finish() {
+        digests.push(write_chunk_if_absent(store_dir, &chunk, level).await?);
+    }
+    if let Some(expected) = &job.expected_digest {
+        let digest = reader.finalize_hex();
+        if !expected.eq_ignore_ascii_case(&digest) {
+            anyhow::bail!(
+                "blake3 mismatch for {}: expected {expected}, got {digest}",
+                job.symbol
+            );
+        }
+    }
+
+    let index = ChunkIndex {
+        symbol: job.symbol.clone(),
+        total_len,
+        chunks: digests,
+    };
+    let path = index_path(store_dir, &job.symbol);
+    tokio::fs::create_dir_all(path.parent().expect("index_path has a parent"))
+        .await
+        .with_context(|| format!("creating index dir for {}", job.symbol))?;
+    tokio::fs::write(&path, serde_json::to_vec_pretty(&index)?)
+        .await
+        .with_context(|| format!("writing chunk index {path:?}"))?;
+
+    Ok(index)
+}
+
+async fn write_chunk_if_absent(store_dir: &Path, chunk: &[u8], level: i32) -> Result<String> {
+    let digest = blake3::hash(chunk).to_hex().to_string();
+    let path = chunk_path(store_dir, &digest);
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Ok(digest);
+    }
+
+    tokio::fs::create_dir_all(path.parent().expect("chunk_path has a parent"))
+        .await
+        .with_context(|| format!("creating chunk dir for {digest}"))?;
+    // Unique per attempt: two concurrent jobs producing the same chunk must
+    // not write-then-rename the same temp path, or a reader could observe a
+    // truncated file mid-write.
+    let tmp_path = path.with_extension(format!("zst.tmp.{}", rand::random::<u64>()));
+    let file = tokio::fs::File::create(&tmp_path)
+        .await
+        .with_context(|| format!("creating {tmp_path:?}"))?;
+    let mut encoder = ZstdEncoder::with_quality(BufWriter::new(file), Level::Precise(level));
+    encoder
+        .write_all(chunk)
+        .await
+        .with_context(|| format!("compressing chunk {digest}"))?;
+    encoder
+        .shutdown()
+        .await
+        .with_context(|| format!("finalizing chunk {digest}"))?;
+    tokio::fs::rename(&tmp_path, &path)
+        .await
+        .with_context(|| format!("renaming chunk {digest} into place"))?;
+
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_boundary_below_min_chunk_size() {
+        let mut chunker = Chunker::new();
+        let boundaries = chunker.push(&vec![0u8; MIN_CHUNK_SIZE - 1]);
+        assert!(boundaries.is_empty());
+        let tail = chunker.finish().expect("buffered bytes remain");
+        assert_eq!(tail.len(), MIN_CHUNK_SIZE - 1);
+    }
+
+    #[test]
+    fn clamps_at_max_chunk_size_even_without_a_hash_boundary() {
+        // All-zero input never sets the Gear hash's boundary bits (GEAR[0]
+        // mixes to a fixed value whose low bits this test doesn't rely on
+        // staying zero at MIN_CHUNK_SIZE), so the only thing that can force a
+        // boundary here is the MAX_CHUNK_SIZE clamp.
+        let mut chunker = Chunker::new();
+        let boundaries = chunker.push(&vec![0u8; MAX_CHUNK_SIZE]);
+        assert_eq!(boundaries.len(), 1);
+        assert_eq!(boundaries[0].len(), MAX_CHUNK_SIZE);
+        assert!(chunker.finish().is_none());
+    }
+
+    #[test]
+    fn boundaries_never_exceed_max_chunk_size() {
+        let mut chunker = Chunker::new();
+        let mut data = Vec::new();
+        for i in 0..(MAX_CHUNK_SIZE * 3) {
+            data.push((i % 256) as u8);
+        }
+        let boundaries = chunker.push(&data);
+        for chunk in &boundaries {
+            assert!(chunk.len() <= MAX_CHUNK_SIZE);
+            assert!(chunk.len() >= MIN_CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn finish_returns_none_on_empty_buffer() {
+        let mut chunker = Chunker::new();
+        assert!(chunker.finish().is_none());
+    }
+
+    #[test]
+    fn finish_drains_the_trailing_partial_chunk() {
+        let mut chunker = Chunker::new();
+        chunker.push(&[1, 2, 3]);
+        let tail = chunker.finish().expect("partial bytes remain");
+        assert_eq!(tail, vec![1, 2, 3]);
+        assert!(chunker.finish().is_none());
+    }
+}
+
+/// Reads a symbol's chunk index and concatenates/decompresses its chunks
+/// back into `output`, the inverse of `run_batch_chunked`.
+pub(crate) async fn reassemble(store_dir: &Path, symbol: &str, output: &Path) -> Result<()> {
+    let path = index_path(store_dir, symbol);
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("reading chunk index {path:?}"))?;
+    let index: ChunkIndex =
+        serde_json::from_str(&text).with_context(|| format!("parsing chunk index {path:?}"))?;
+
+    if let Some(parent) = output.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("creating parent dir {parent:?}"))?;
+    }
+    let file = tokio::fs::File::create(output)
+        .await
+        .with_context(|| format!("creating {output:?}"))?;
+    let mut writer = BufWriter::new(file);
+    let mut written = 0u64;
+    for digest in &index.chunks {
+        let chunk_path = chunk_path(store_dir, digest);
+        let compressed = tokio::fs::File::open(&chunk_path)
+            .await
+            .with_context(|| format!("missing chunk {digest} for {symbol}"))?;
+        let mut decoder = ZstdDecoder::new(BufReader::new(compressed));
+        written += io::copy(&mut decoder, &mut writer)
+            .await
+            .with_context(|| format!("decompressing chunk {digest}"))?;
+    }
+    writer.flush().await.context("flushing reassembled output")?;
+
+    if written != index.total_len {
+        anyhow::bail!(
+            "reassembled {symbol} is {written} bytes, expected {}",
+            index.total_len
+        );
+    }
+    Ok(())
+}
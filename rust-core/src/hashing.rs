@@ -0,0 +1,88 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Wraps an `AsyncRead` and feeds every byte that passes through into a
+/// BLAKE3 hasher, so callers get an integrity digest of the *uncompressed*
+/// body with no extra read pass over the stream.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// Hex-encoded digest of everything read so far.
+    pub fn finalize_hex(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            self.hasher.update(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+/// Sidecar file next to a compressed output holding its BLAKE3 digest, e.g.
+/// `AAPL.zst` -> `AAPL.zst.b3`.
+pub fn digest_sidecar_path(output: &Path) -> PathBuf {
+    append_extension(output, "b3")
+}
+
+/// Sidecar file caching the validator (`ETag`) of the last successful fetch,
+/// used to issue conditional requests on re-runs.
+pub fn etag_sidecar_path(output: &Path) -> PathBuf {
+    append_extension(output, "etag")
+}
+
+fn append_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+/// Reads a previously stored digest, if any, ignoring a missing file.
+pub async fn read_sidecar(path: &Path) -> Option<String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+pub async fn write_sidecar(path: &Path, contents: &str) -> std::io::Result<()> {
+    tokio::fs::write(path, contents).await
+}
+
+/// Decompresses a finished `.zst` file and hashes the original body. Used to
+/// verify/derive the digest for a download that was resumed across process
+/// restarts, where the live tee hasher couldn't carry its state over.
+pub async fn hash_compressed_file(path: &Path) -> std::io::Result<String> {
+    let file = tokio::fs::File::open(path).await?;
+    let decoder =
+        async_compression::tokio::bufread::ZstdDecoder::new(tokio::io::BufReader::new(file));
+    let mut reader = HashingReader::new(decoder);
+    tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    Ok(reader.finalize_hex())
+}
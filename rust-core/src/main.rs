@@ -1,10 +1,10 @@
-use std::{path::PathBuf, time::Duration};
+use std::{path::PathBuf, sync::atomic::Ordering, time::Duration};
 
 use anyhow::{Context, Result};
 use async_compression::{tokio::write::ZstdEncoder, Level};
 use clap::{Parser, Subcommand};
 use futures::StreamExt;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::Deserialize;
 use std::collections::HashMap;
 use tokio::{
@@ -17,6 +17,15 @@ use tokio_util::io::StreamReader;
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
+mod archive;
+mod bench;
+mod chunkstore;
+mod hashing;
+mod ratelimit;
+mod resume;
+use hashing::HashingReader;
+use ratelimit::RateLimiter;
+
 #[derive(Parser, Debug)]
 #[command(
     name = "dump-core",
@@ -36,6 +45,22 @@ struct Cli {
     #[arg(long, default_value_t = 2)]
     retries: u32,
 
+    /// Default max requests/sec per host, unless overridden by --host-rps
+    #[arg(long, value_parser = ratelimit::positive_rate)]
+    max_rps: Option<f64>,
+
+    /// Default max bytes/sec per host, unless overridden by --host-bps
+    #[arg(long, value_parser = ratelimit::positive_rate)]
+    max_bps: Option<f64>,
+
+    /// Per-host requests/sec override, repeatable, as "host=rate"
+    #[arg(long = "host-rps", value_parser = ratelimit::parse_host_rate)]
+    host_rps: Vec<(String, f64)>,
+
+    /// Per-host bytes/sec override, repeatable, as "host=rate"
+    #[arg(long = "host-bps", value_parser = ratelimit::parse_host_rate)]
+    host_bps: Vec<(String, f64)>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -65,15 +90,63 @@ enum Commands {
         /// Compression level (-7..=22). Higher = better ratio, slower.
         #[arg(long, default_value_t = 3)]
         level: i32,
+        /// Pack every symbol into one streaming ZIP archive instead of one
+        /// `.zst` file per symbol under `output_dir`
+        #[arg(long)]
+        archive: Option<PathBuf>,
+        /// Split each symbol's body into content-defined, deduplicated
+        /// chunks under this directory instead of writing `output_dir`
+        #[arg(long)]
+        chunk_store: Option<PathBuf>,
+    },
+    /// Rebuild a symbol's original body from a `--chunk-store` index
+    Reassemble {
+        /// Chunk store directory used with `batch --chunk-store`
+        #[arg(long)]
+        chunk_store: PathBuf,
+        /// Symbol whose index should be reassembled
+        #[arg(long)]
+        symbol: String,
+        /// Where to write the reassembled original body
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Replay workload files through the fetch pipeline and report timing
+    Bench {
+        /// One or more JSON workload files to replay
+        #[arg(long = "workload", required = true)]
+        workloads: Vec<PathBuf>,
+        /// Directory to write machine-readable report JSON into
+        #[arg(long, default_value = "bench-reports")]
+        report_dir: PathBuf,
+        /// Optional URL to POST each report to
+        #[arg(long)]
+        dashboard_url: Option<String>,
+        /// Compression level (-7..=22). Higher = better ratio, slower.
+        #[arg(long, default_value_t = 3)]
+        level: i32,
     },
 }
 
 #[derive(Debug, Deserialize, Clone)]
-struct Job {
-    symbol: String,
-    url: String,
+pub(crate) struct Job {
+    pub(crate) symbol: String,
+    pub(crate) url: String,
     #[serde(default)]
-    headers: Option<HashMap<String, String>>,
+    pub(crate) headers: Option<HashMap<String, String>>,
+    /// Expected BLAKE3 digest (hex) of the uncompressed body. When set, a
+    /// mismatch fails the job instead of silently writing bad data.
+    #[serde(default, rename = "blake3", alias = "sha")]
+    pub(crate) expected_digest: Option<String>,
+}
+
+/// Per-job outcome metadata, used by `bench` to build timing/throughput
+/// reports without changing what `Single`/`Batch` see (they just check
+/// `Ok`/`Err`).
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FetchStats {
+    pub(crate) bytes_downloaded: u64,
+    pub(crate) bytes_written: u64,
 }
 
 #[tokio::main]
@@ -95,6 +168,12 @@ async fn main() -> Result<()> {
         .build()
         .context("building HTTP client")?;
 
+    let limiter = std::sync::Arc::new(RateLimiter::new(
+        cli.max_rps,
+        cli.max_bps,
+        ratelimit::merge_host_overrides(&cli.host_rps, &cli.host_bps),
+    ));
+
     match cli.command {
         Commands::Single { url, output, level } => {
             let job = Job {
@@ -105,44 +184,75 @@ async fn main() -> Result<()> {
                     .to_string(),
                 url,
                 headers: None,
+                expected_digest: None,
             };
-            fetch_and_write(&client, job, output, level, cli.retries).await?;
+            fetch_and_write(&client, job, output, level, cli.retries, &limiter).await?;
         }
         Commands::Batch {
             manifest,
             output_dir,
             level,
+            archive,
+            chunk_store,
         } => {
             let jobs = read_manifest(&manifest)
                 .with_context(|| format!("reading manifest {manifest:?}"))?;
-            tokio::fs::create_dir_all(&output_dir)
-                .await
-                .with_context(|| format!("creating output dir {output_dir:?}"))?;
-
-            let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(cli.concurrency));
-            let mut ok = 0usize;
-            let mut failed = 0usize;
-
-            let mut stream = futures::stream::iter(jobs.into_iter().map(|job| {
-                let client = client.clone();
-                let output_dir = output_dir.clone();
-                let semaphore = semaphore.clone();
-                async move {
-                    let _permit = semaphore.acquire().await.expect("semaphore closed");
-                    let output = output_dir.join(format!("{}.zst", job.symbol));
-                    fetch_and_write(&client, job.clone(), output, level, cli.retries).await
-                }
-            }))
-            .buffer_unordered(cli.concurrency);
-
-            while let Some(result) = stream.next().await {
-                if let Err(err) = result {
-                    warn!("job failed: {err:?}");
-                    failed += 1;
-                } else {
-                    ok += 1;
+
+            let (ok, failed) = if let Some(store_dir) = chunk_store {
+                chunkstore::run_batch_chunked(
+                    &client,
+                    jobs,
+                    store_dir,
+                    level,
+                    cli.concurrency,
+                    cli.retries,
+                    &limiter,
+                )
+                .await?
+            } else if let Some(archive_path) = archive {
+                archive::run_batch_archive(
+                    &client,
+                    jobs,
+                    archive_path,
+                    level,
+                    cli.concurrency,
+                    cli.retries,
+                    limiter.clone(),
+                )
+                .await?
+            } else {
+                tokio::fs::create_dir_all(&output_dir)
+                    .await
+                    .with_context(|| format!("creating output dir {output_dir:?}"))?;
+
+                let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(cli.concurrency));
+                let mut ok = 0usize;
+                let mut failed = 0usize;
+
+                let mut stream = futures::stream::iter(jobs.into_iter().map(|job| {
+                    let client = client.clone();
+                    let output_dir = output_dir.clone();
+                    let semaphore = semaphore.clone();
+                    let limiter = limiter.clone();
+                    async move {
+                        let _permit = semaphore.acquire().await.expect("semaphore closed");
+                        let output = output_dir.join(format!("{}.zst", job.symbol));
+                        fetch_and_write(&client, job.clone(), output, level, cli.retries, &limiter)
+                            .await
+                    }
+                }))
+                .buffer_unordered(cli.concurrency);
+
+                while let Some(result) = stream.next().await {
+                    if let Err(err) = result {
+                        warn!("job failed: {err:?}");
+                        failed += 1;
+                    } else {
+                        ok += 1;
+                    }
                 }
-            }
+                (ok, failed)
+            };
 
             if failed > 0 {
                 error!("batch done: {ok} ok, {failed} failed");
@@ -151,39 +261,104 @@ async fn main() -> Result<()> {
                 info!("batch done: {ok} ok");
             }
         }
+        Commands::Reassemble {
+            chunk_store,
+            symbol,
+            output,
+        } => {
+            chunkstore::reassemble(&chunk_store, &symbol, &output).await?;
+            info!("reassembled {symbol} -> {}", output.display());
+        }
+        Commands::Bench {
+            workloads,
+            report_dir,
+            dashboard_url,
+            level,
+        } => {
+            for workload_path in workloads {
+                let report =
+                    bench::run_workload(&client, &workload_path, level, cli.retries, &limiter)
+                        .await
+                        .with_context(|| format!("running workload {workload_path:?}"))?;
+                bench::log_summary(&report);
+                let report_path = bench::write_report(&report_dir, &report).await?;
+                info!("wrote bench report to {}", report_path.display());
+                if let Some(url) = &dashboard_url {
+                    bench::post_report(url, &report).await?;
+                }
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn fetch_and_write(
+pub(crate) async fn fetch_and_write(
     client: &Client,
     job: Job,
     output: PathBuf,
     level: i32,
     retries: u32,
-) -> Result<()> {
+    limiter: &RateLimiter,
+) -> Result<FetchStats> {
     let mut attempts = 0;
     loop {
         attempts += 1;
-        match fetch_once(client, &job, &output, level).await {
-            Ok(_) => {
+        match fetch_once(client, &job, &output, level, limiter).await {
+            Ok(stats) => {
                 info!("✔ {} -> {}", job.symbol, output.display());
-                return Ok(());
+                return Ok(stats);
             }
             Err(err) if attempts <= retries => {
+                let wait = ratelimit::backoff_for(&err, attempts);
                 warn!(
-                    "retry {}/{} for {} due to {err:?}",
-                    attempts, retries, job.symbol
+                    "retry {}/{} for {} in {:?} due to {err:?}",
+                    attempts, retries, job.symbol, wait
                 );
-                tokio::time::sleep(Duration::from_millis(200 * attempts as u64)).await;
+                tokio::time::sleep(wait).await;
             }
             Err(err) => return Err(err),
         }
     }
 }
 
-async fn fetch_once(client: &Client, job: &Job, output: &PathBuf, level: i32) -> Result<()> {
+async fn fetch_once(
+    client: &Client,
+    job: &Job,
+    output: &PathBuf,
+    level: i32,
+    limiter: &RateLimiter,
+) -> Result<FetchStats> {
+    let digest_path = hashing::digest_sidecar_path(output);
+    let etag_path = hashing::etag_sidecar_path(output);
+    let part_path = resume::part_path(output);
+    let state_path = resume::state_path(output);
+
+    let prior_etag = hashing::read_sidecar(&etag_path).await;
+    let prior_state = resume::load(&state_path).await;
+    let part_exists = tokio::fs::metadata(&part_path).await.is_ok();
+
+    // `bytes_received` (uncompressed source bytes already downloaded) drives
+    // the `Range` offset below, never the `.part` file's on-disk length —
+    // the `.part` is zstd-compressed, so its size has no relation to how far
+    // into the source body we got. There is no standalone "resume from this
+    // offset" override: without a real `.part` holding that prefix on disk,
+    // an offset has nothing to append to, so resume only ever comes from a
+    // `.part` paired with a validated state sidecar (see `resume::resume_offset`).
+    let mut offset = match resume::resume_offset(part_exists, prior_state.as_ref()) {
+        Some(offset) => offset,
+        None => {
+            if part_exists {
+                warn!(
+                    "{} has a .part with no valid resume state, refetching from scratch",
+                    job.symbol
+                );
+                resume::clear(&part_path, &state_path).await;
+            }
+            0
+        }
+    };
+
     let mut req = client
         .get(&job.url)
         .header(
@@ -195,20 +370,75 @@ async fn fetch_once(client: &Client, job: &Job, output: &PathBuf, level: i32) ->
             req = req.header(k, v);
         }
     }
-    let resp = req.send().await.with_context(|| format!("requesting {}", job.url))?;
+    if offset > 0 {
+        req = req.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        // `bytes_received`/`offset` count decoded bytes, but `Range` addresses
+        // the encoded resource; with the client's default `gzip(true)` those
+        // disagree on a compressed response. Force identity encoding so the
+        // range we ask for and the bytes we splice onto the `.part` line up.
+        req = req.header(reqwest::header::ACCEPT_ENCODING, "identity");
+        if let Some(validator) = prior_state
+            .as_ref()
+            .and_then(|s| s.etag.clone().or_else(|| s.last_modified.clone()))
+        {
+            req = req.header(reqwest::header::IF_RANGE, validator);
+        }
+    } else if let Some(etag) = &prior_etag {
+        if job
+            .headers
+            .as_ref()
+            .map_or(true, |h| !h.contains_key("If-None-Match"))
+        {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+    }
 
+    limiter.acquire_request(&job.url).await;
+    let resp = req.send().await.with_context(|| format!("requesting {}", job.url))?;
     let status = resp.status();
-    if !status.is_success() {
+
+    if offset == 0 && status == StatusCode::NOT_MODIFIED {
+        info!("↷ {} unchanged, skipping re-download", job.symbol);
+        return Ok(FetchStats::default());
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = ratelimit::parse_retry_after(resp.headers());
+        return Err(ratelimit::RateLimited { status, retry_after }.into())
+            .with_context(|| format!("rate limited requesting {}", job.url));
+    }
+
+    let resuming = offset > 0 && status == StatusCode::PARTIAL_CONTENT;
+    if offset > 0 && status == StatusCode::OK {
+        // Server ignored the Range request or the validator changed underfoot:
+        // the partial data we hold is no longer usable, start over.
+        warn!("{} can't resume ({}), refetching from scratch", job.symbol, status);
+        resume::clear(&part_path, &state_path).await;
+        offset = 0;
+    } else if offset > 0 && !resuming {
+        anyhow::bail!("unexpected status {} resuming {}", status, job.url);
+    } else if offset == 0 && !status.is_success() {
         anyhow::bail!("non-2xx {} for {}", status, job.url);
     }
 
-    // Wrap streaming body into AsyncRead
+    let etag = resp
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let last_modified = resp
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    // Wrap streaming body into AsyncRead, paced to this host's --max-bps budget
     let byte_stream = resp.bytes_stream().map(|res| {
         res.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("http stream error: {e}")))
     });
-    let mut reader = StreamReader::new(byte_stream);
+    let reader = limiter.wrap_reader(&job.url, StreamReader::new(byte_stream));
+    let (mut reader, bytes_this_attempt) = resume::CountingReader::new(reader);
 
-    // Use blocking file creation to avoid partial writes on failure
     let parent = output
         .parent()
         .map(PathBuf::from)
@@ -217,35 +447,104 @@ async fn fetch_once(client: &Client, job: &Job, output: &PathBuf, level: i32) ->
         .await
         .with_context(|| format!("creating parent dir {parent:?}"))?;
 
-    let file = File::create(output)
-        .await
-        .with_context(|| format!("creating {}", output.display()))?;
+    let file = if resuming {
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&part_path)
+            .await
+            .with_context(|| format!("reopening partial {part_path:?}"))?
+    } else {
+        File::create(&part_path)
+            .await
+            .with_context(|| format!("creating {part_path:?}"))?
+    };
     let writer = BufWriter::new(file);
+    // A fresh zstd frame is appended each session; zstd transparently
+    // decodes a file made of concatenated frames, so resuming never needs
+    // to reconstruct the previous encoder's internal state.
     let mut encoder = ZstdEncoder::with_quality(writer, Level::Precise(level));
 
-    // Copy bytes through compressor
-    io::copy(&mut reader, &mut encoder)
-        .await
-        .with_context(|| format!("writing {}", output.display()))?;
+    // Hashing-as-you-stream (no extra pass) only covers a session that saw
+    // the whole body from byte 0; a resumed continuation can't recover the
+    // hasher state of a prior process, so it's verified below instead.
+    let (copy_result, live_digest) = if offset == 0 {
+        let mut hashing_reader = HashingReader::new(reader);
+        let result = io::copy(&mut hashing_reader, &mut encoder).await;
+        (result, Some(hashing_reader.finalize_hex()))
+    } else {
+        let result = io::copy(&mut reader, &mut encoder).await;
+        (result, None)
+    };
+
+    let copied = match copy_result {
+        Ok(n) => n,
+        Err(err) => {
+            let _ = encoder.shutdown().await;
+            let state = resume::ResumeState {
+                bytes_received: offset + bytes_this_attempt.load(Ordering::Relaxed),
+                etag: etag.or_else(|| prior_state.as_ref().and_then(|s| s.etag.clone())),
+                last_modified: last_modified
+                    .or_else(|| prior_state.as_ref().and_then(|s| s.last_modified.clone())),
+            };
+            let _ = resume::save(&state_path, &state).await;
+            return Err(err).with_context(|| format!("streaming {}", job.url));
+        }
+    };
     encoder
         .shutdown()
         .await
         .context("finalizing compressed stream")?;
 
-    // Force fsync on completion
-    let output_clone = output.clone();
+    // Force fsync before the rename below makes the file visible under its
+    // final name.
+    let part_path_clone = part_path.clone();
     task::spawn_blocking(move || {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
-            .open(&output_clone)?;
+            .open(&part_path_clone)?;
         file.sync_all()?;
         Result::<()>::Ok(())
     })
     .await
     .context("fsync join")??;
 
-    Ok(())
+    tokio::fs::rename(&part_path, output)
+        .await
+        .with_context(|| format!("renaming {part_path:?} to {output:?}"))?;
+    let _ = tokio::fs::remove_file(&state_path).await;
+
+    let digest = match live_digest {
+        Some(d) => d,
+        None => hashing::hash_compressed_file(output)
+            .await
+            .with_context(|| format!("hashing resumed {output:?}"))?,
+    };
+    if let Some(expected) = &job.expected_digest {
+        if !expected.eq_ignore_ascii_case(&digest) {
+            let _ = tokio::fs::remove_file(output).await;
+            anyhow::bail!(
+                "blake3 mismatch for {}: expected {expected}, got {digest}",
+                job.symbol
+            );
+        }
+    }
+    hashing::write_sidecar(&digest_path, &digest)
+        .await
+        .with_context(|| format!("writing digest sidecar {digest_path:?}"))?;
+    if let Some(etag) = etag {
+        hashing::write_sidecar(&etag_path, &etag)
+            .await
+            .with_context(|| format!("writing etag sidecar {etag_path:?}"))?;
+    }
+
+    tracing::debug!("{} wrote {} bytes this session (offset {offset})", job.symbol, copied);
+
+    let bytes_written = tokio::fs::metadata(output).await.map(|m| m.len()).unwrap_or(0);
+    Ok(FetchStats {
+        bytes_downloaded: offset + copied,
+        bytes_written,
+    })
 }
 
 fn read_manifest(path: &PathBuf) -> Result<Vec<Job>> {
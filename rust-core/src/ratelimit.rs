@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use reqwest::{header::HeaderMap, StatusCode};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::Sleep,
+};
+
+/// A classic token bucket: `capacity` tokens refilling at `refill_per_sec`,
+/// shared across jobs that hit the same host via `Arc`.
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// `rate_per_sec` must be finite and positive; callers validate this at
+    /// the CLI boundary (see [`positive_rate`]) so it never reaches here.
+    fn new(rate_per_sec: f64) -> Self {
+        debug_assert!(rate_per_sec.is_finite() && rate_per_sec > 0.0);
+        let capacity = rate_per_sec.max(1.0);
+        Self {
+            capacity,
+            refill_per_sec: rate_per_sec,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `amount` tokens now if available; otherwise returns how long
+    /// the caller should wait before trying again.
+    fn try_consume(&self, amount: f64) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("token bucket mutex poisoned");
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        state.last_refill = now;
+
+        if state.tokens >= amount {
+            state.tokens -= amount;
+            Ok(())
+        } else {
+            let deficit = amount - state.tokens;
+            Err(Duration::from_secs_f64(deficit / self.refill_per_sec))
+        }
+    }
+
+    async fn acquire(&self, amount: f64) {
+        loop {
+            match self.try_consume(amount) {
+                Ok(()) => return,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+struct HostBuckets {
+    rps: Option<Arc<TokenBucket>>,
+    bps: Option<Arc<TokenBucket>>,
+}
+
+/// Per-host request-rate and byte-rate budgets, so one slow/strict upstream
+/// doesn't throttle fetches against every other data provider in the batch.
+pub(crate) struct RateLimiter {
+    default_rps: Option<f64>,
+    default_bps: Option<f64>,
+    per_host: HashMap<String, (Option<f64>, Option<f64>)>,
+    buckets: Mutex<HashMap<String, Arc<HostBuckets>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(
+        default_rps: Option<f64>,
+        default_bps: Option<f64>,
+        per_host: HashMap<String, (Option<f64>, Option<f64>)>,
+    ) -> Self {
+        Self {
+            default_rps,
+            default_bps,
+            per_host,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn buckets_for(&self, host: &str) -> Arc<HostBuckets> {
+        let mut buckets = self.buckets.lock().expect("rate limiter mutex poisoned");
+        if let Some(existing) = buckets.get(host) {
+            return existing.clone();
+        }
+        let (rps, bps) = self
+            .per_host
+            .get(host)
+            .copied()
+            .unwrap_or((self.default_rps, self.default_bps));
+        let created = Arc::new(HostBuckets {
+            rps: rps.map(|r| Arc::new(TokenBucket::new(r))),
+            bps: bps.map(|b| Arc::new(TokenBucket::new(b))),
+        });
+        buckets.insert(host.to_string(), created.clone());
+        created
+    }
+
+    /// Awaits this host's request-rate budget before a new HTTP request.
+    pub(crate) async fn acquire_request(&self, url: &str) {
+        let Some(host) = host_of(url) else { return };
+        if let Some(rps) = &self.buckets_for(&host).rps {
+            rps.acquire(1.0).await;
+        }
+    }
+
+    /// Wraps a response body reader so streaming it is paced to this host's
+    /// byte-rate budget, if one is configured.
+    pub(crate) fn wrap_reader<R: AsyncRead + Unpin>(
+        &self,
+        url: &str,
+        inner: R,
+    ) -> RateLimitedReader<R> {
+        let bucket = host_of(url).and_then(|host| self.buckets_for(&host).bps.clone());
+        RateLimitedReader {
+            inner,
+            bucket,
+            sleep: None,
+        }
+    }
+}
+
+fn host_of(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+}
+
+/// A `clap` value parser for `--max-rps`/`--max-bps`: rejects zero, negative,
+/// or non-finite rates so [`TokenBucket`] never has to divide by zero.
+pub(crate) fn positive_rate(spec: &str) -> std::result::Result<f64, String> {
+    let rate: f64 = spec
+        .parse()
+        .map_err(|_| format!("invalid rate {spec:?}"))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        return Err(format!("rate must be a positive number, got {spec:?}"));
+    }
+    Ok(rate)
+}
+
+/// Parses a CLI override in `host=rate` form, as used by `--host-rps` and
+/// `--host-bps`.
+pub(crate) fn parse_host_rate(spec: &str) -> std::result::Result<(String, f64), String> {
+    let (host, rate) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected host=rate, got {spec:?}"))?;
+    Ok((host.to_string(), positive_rate(rate)?))
+}
+
+pub(crate) fn merge_host_overrides(
+    rps: &[(String, f64)],
+    bps: &[(String, f64)],
+) -> HashMap<String, (Option<f64>, Option<f64>)> {
+    let mut merged: HashMap<String, (Option<f64>, Option<f64>)> = HashMap::new();
+    for (host, rate) in rps {
+        merged.entry(host.clone()).or_default().0 = Some(*rate);
+    }
+    for (host, rate) in bps {
+        merged.entry(host.clone()).or_default().1 = Some(*rate);
+    }
+    merged
+}
+
+/// An `AsyncRead` that blocks each read on this host's byte-rate budget, so
+/// the underlying stream never delivers faster than `--max-bps` allows.
+pub(crate) struct RateLimitedReader<R> {
+    inner: R,
+    bucket: Option<Arc<TokenBucket>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RateLimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let Some(bucket) = self.bucket.clone() else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                match sleep.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(()) => self.sleep = None,
+                }
+            }
+            // Budget for the read we're about to issue, capped so one huge
+            // buffer can't reserve an unreasonable number of future tokens.
+            let wanted = buf.remaining().min(64 * 1024).max(1) as f64;
+            match bucket.try_consume(wanted) {
+                Ok(()) => return Pin::new(&mut self.inner).poll_read(cx, buf),
+                Err(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+/// A non-2xx response that signals upstream rate limiting, carrying however
+/// long the server asked callers to wait before retrying.
+#[derive(Debug)]
+pub(crate) struct RateLimited {
+    pub(crate) status: StatusCode,
+    pub(crate) retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate limited ({})", self.status)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Parses `Retry-After` in either delta-seconds (`"120"`) or HTTP-date
+/// (`"Wed, 21 Oct 2015 07:28:00 GMT"`) form.
+pub(crate) fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    let trimmed = value.trim();
+    if let Ok(secs) = trimmed.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(trimmed).ok()?;
+    when.duration_since(std::time::SystemTime::now()).ok()
+}
+
+/// Longest we'll honor an upstream's `Retry-After`; a misbehaving or
+/// compromised server asking for an absurd delay shouldn't be able to wedge
+/// a job's retry loop indefinitely.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Looks for a `RateLimited` error anywhere in the chain (so it survives
+/// `.with_context(...)` wrapping) and uses its `Retry-After` if present;
+/// otherwise falls back to capped exponential backoff with full jitter.
+pub(crate) fn backoff_for(err: &anyhow::Error, attempt: u32) -> Duration {
+    if let Some(limited) = err.chain().find_map(|cause| cause.downcast_ref::<RateLimited>()) {
+        if let Some(retry_after) = limited.retry_after {
+            return retry_after.min(MAX_RETRY_AFTER);
+        }
+    }
+    exponential_backoff_with_jitter(attempt)
+}
+
+fn exponential_backoff_with_jitter(attempt: u32) -> Duration {
+    const BASE_MS: u64 = 200;
+    const CAP_MS: u64 = 30_000;
+    let exp = attempt.saturating_sub(1).min(8);
+    let backoff_ms = BASE_MS.saturating_mul(1u64 << exp).min(CAP_MS);
+    let jittered_ms = rand::random::<u64>() % backoff_ms.max(1);
+    Duration::from_millis(jittered_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_consume_takes_tokens_up_to_capacity() {
+        let bucket = TokenBucket::new(10.0);
+        assert!(bucket.try_consume(10.0).is_ok());
+        assert!(bucket.try_consume(0.1).is_err());
+    }
+
+    #[test]
+    fn try_consume_reports_wait_proportional_to_deficit() {
+        let bucket = TokenBucket::new(10.0);
+        bucket.try_consume(10.0).unwrap();
+        let wait = bucket.try_consume(5.0).unwrap_err();
+        // deficit 5.0 at refill_per_sec 10.0 -> 0.5s
+        assert!((wait.as_secs_f64() - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn try_consume_refills_over_time() {
+        let bucket = TokenBucket::new(10.0);
+        bucket.try_consume(10.0).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        // ~1.5 tokens should have refilled by now
+        assert!(bucket.try_consume(1.0).is_ok());
+    }
+
+    #[test]
+    fn try_consume_never_refills_past_capacity() {
+        let bucket = TokenBucket::new(5.0);
+        std::thread::sleep(Duration::from_millis(50));
+        // capacity is max(rate, 1.0) = 5.0, so this must still fail even
+        // though plenty of wall-clock time has passed to overflow it.
+        assert!(bucket.try_consume(5.1).is_err());
+    }
+
+    fn headers_with_retry_after(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::RETRY_AFTER,
+            value.parse().expect("valid header value"),
+        );
+        headers
+    }
+
+    #[test]
+    fn parses_delta_seconds_retry_after() {
+        let headers = headers_with_retry_after("120");
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parses_http_date_retry_after_in_the_future() {
+        let future = std::time::SystemTime::now() + Duration::from_secs(60);
+        let formatted = httpdate::fmt_http_date(future);
+        let headers = headers_with_retry_after(&formatted);
+        let parsed = parse_retry_after(&headers).expect("future date should parse");
+        // allow slack for the time elapsed between formatting and parsing
+        assert!(parsed.as_secs() <= 60 && parsed.as_secs() >= 55);
+    }
+
+    #[test]
+    fn rejects_http_date_retry_after_in_the_past() {
+        let past = std::time::SystemTime::now() - Duration::from_secs(60);
+        let formatted = httpdate::fmt_http_date(past);
+        let headers = headers_with_retry_after(&formatted);
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn missing_retry_after_header_is_none() {
+        let headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+}
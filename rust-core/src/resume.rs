@@ -0,0 +1,151 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// State sidecar recorded next to a `.part` file so an interrupted fetch can
+/// be resumed instead of restarted from scratch.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResumeState {
+    pub bytes_received: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// `<symbol>.zst` -> `<symbol>.zst.part`, the compressed stream as written
+/// so far.
+pub fn part_path(output: &Path) -> PathBuf {
+    with_suffix(output, "part")
+}
+
+/// `<symbol>.zst` -> `<symbol>.zst.resume`, the JSON state sidecar.
+pub fn state_path(output: &Path) -> PathBuf {
+    with_suffix(output, "resume")
+}
+
+fn with_suffix(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+pub async fn load(state_path: &Path) -> Option<ResumeState> {
+    let text = tokio::fs::read_to_string(state_path).await.ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+pub async fn save(state_path: &Path, state: &ResumeState) -> std::io::Result<()> {
+    let text = serde_json::to_string(state).expect("ResumeState always serializes");
+    tokio::fs::write(state_path, text).await
+}
+
+pub async fn clear(part_path: &Path, state_path: &Path) {
+    let _ = tokio::fs::remove_file(part_path).await;
+    let _ = tokio::fs::remove_file(state_path).await;
+}
+
+/// Decides whether an on-disk `.part` can be resumed. A state sidecar must
+/// carry a validator (etag or last-modified), or a stale/changed upstream
+/// body could be silently spliced onto the partial download — so this
+/// returns the byte offset to resume from only when both the `.part` and a
+/// validated sidecar are present, and `None` otherwise (the caller should
+/// clear any orphaned `.part` and start over from scratch).
+pub fn resume_offset(part_exists: bool, prior_state: Option<&ResumeState>) -> Option<u64> {
+    if !part_exists {
+        return None;
+    }
+    let has_validator = prior_state.map_or(false, |s| s.etag.is_some() || s.last_modified.is_some());
+    if !has_validator {
+        return None;
+    }
+    Some(prior_state.map(|s| s.bytes_received).unwrap_or(0))
+}
+
+/// Wraps an `AsyncRead` and counts the uncompressed source bytes that pass
+/// through, via a shared counter that stays readable even if the stream
+/// errors out partway — the count a failed attempt got to is exactly what a
+/// retry's resume offset should pick up from.
+pub struct CountingReader<R> {
+    inner: R,
+    counter: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> (Self, Arc<AtomicU64>) {
+        let counter = Arc::new(AtomicU64::new(0));
+        (
+            Self {
+                inner,
+                counter: counter.clone(),
+            },
+            counter,
+        )
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            let read = (buf.filled().len() - before) as u64;
+            self.counter.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(etag: Option<&str>, last_modified: Option<&str>, bytes_received: u64) -> ResumeState {
+        ResumeState {
+            bytes_received,
+            etag: etag.map(str::to_string),
+            last_modified: last_modified.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn no_part_means_no_resume() {
+        assert_eq!(resume_offset(false, None), None);
+        assert_eq!(resume_offset(false, Some(&state(Some("v1"), None, 100))), None);
+    }
+
+    #[test]
+    fn part_without_state_means_no_resume() {
+        assert_eq!(resume_offset(true, None), None);
+    }
+
+    #[test]
+    fn part_with_unvalidated_state_means_no_resume() {
+        let s = state(None, None, 100);
+        assert_eq!(resume_offset(true, Some(&s)), None);
+    }
+
+    #[test]
+    fn part_with_etag_resumes_at_bytes_received() {
+        let s = state(Some("v1"), None, 100);
+        assert_eq!(resume_offset(true, Some(&s)), Some(100));
+    }
+
+    #[test]
+    fn part_with_last_modified_resumes_at_bytes_received() {
+        let s = state(None, Some("Wed, 21 Oct 2015 07:28:00 GMT"), 42);
+        assert_eq!(resume_offset(true, Some(&s)), Some(42));
+    }
+}